@@ -1,18 +1,122 @@
 use std::marker::PhantomData;
-use libc::{ c_uint, c_int, c_uchar, c_void };
+use libc::{ c_uint, c_int, c_uchar, c_void, timeval, time_t, suseconds_t };
 use std::time::Duration;
 use std::slice;
-use std::sync::Mutex;
+use std::sync::{ Mutex, Arc };
 use std::collections::{ VecDeque, HashSet };
 use std::cell::UnsafeCell;
-use std::mem;
+
+/// A buffer that can back a `Transfer`.
+///
+/// Implemented for `Vec<u8>`, fixed-size arrays whose length is a power of two from 1 to 65536,
+/// and `Box<[u8]>` so transfers aren't forced onto
+/// the heap through a `Vec` when a stack array or a reused pool buffer would do, and for
+/// `ControlSetup`, which bundles a control transfer's setup packet with its payload.
+pub trait TransferBuffer {
+    /// A mutable pointer to the start of the buffer.
+    fn as_ptr(&mut self) -> *mut u8;
+
+    /// The length of the buffer in bytes.
+    fn len(&self) -> usize;
+
+    /// Number of bytes at the start of the buffer that precede the transfer's data stage and so
+    /// should be skipped by `Transfer::actual`. Zero except for `ControlSetup`, whose first 8
+    /// bytes are the setup packet rather than payload.
+    fn data_offset(&self) -> usize { 0 }
+
+    /// A mutable view of the whole buffer.
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        let len = TransferBuffer::len(self);
+        unsafe { slice::from_raw_parts_mut(self.as_ptr(), len) }
+    }
+}
+
+impl TransferBuffer for Vec<u8> {
+    fn as_ptr(&mut self) -> *mut u8 { self.as_mut_slice().as_mut_ptr() }
+    fn len(&self) -> usize { Vec::len(self) }
+}
+
+impl TransferBuffer for Box<[u8]> {
+    fn as_ptr(&mut self) -> *mut u8 { self.as_mut().as_mut_ptr() }
+    fn len(&self) -> usize { (**self).len() }
+}
+
+macro_rules! transfer_buffer_array_impls {
+    ($($n:expr),*) => {
+        $(
+            impl TransferBuffer for [u8; $n] {
+                fn as_ptr(&mut self) -> *mut u8 { (self as &mut [u8]).as_mut_ptr() }
+                fn len(&self) -> usize { $n }
+            }
+        )*
+    }
+}
+
+transfer_buffer_array_impls!(
+    1, 2, 4, 8, 16, 32, 64, 128, 256, 512, 1024, 2048, 4096, 8192, 16384, 32768, 65536
+);
+
+/// The setup packet and payload of a control transfer, laid out in a single allocation the way
+/// libusb expects to find them: `bmRequestType`, `bRequest`, `wValue`, `wIndex`, `wLength`,
+/// followed directly by the payload.
+pub struct ControlSetup {
+    data: Vec<u8>,
+}
+
+impl ControlSetup {
+    /// Allocates a setup packet for `request_type`/`request`/`value`/`index`, with `length`
+    /// zeroed bytes reserved after it for the payload.
+    pub fn new(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> ControlSetup {
+        let mut data = Vec::with_capacity(8 + length as usize);
+        data.push(request_type);
+        data.push(request);
+        data.push((value & 0xff) as u8);
+        data.push((value >> 8) as u8);
+        data.push((index & 0xff) as u8);
+        data.push((index >> 8) as u8);
+        data.push((length & 0xff) as u8);
+        data.push((length >> 8) as u8);
+        data.resize(8 + length as usize, 0);
+        ControlSetup { data: data }
+    }
+
+    /// Allocates a setup packet for `request_type`/`request`/`value`/`index` with `payload`
+    /// copied in as the data stage, for an OUT transfer.
+    pub fn with_data(request_type: u8, request: u8, value: u16, index: u16, payload: &[u8]) -> ControlSetup {
+        let mut setup = ControlSetup::new(request_type, request, value, index, payload.len() as u16);
+        setup.data_mut().copy_from_slice(payload);
+        setup
+    }
+
+    pub fn request_type(&self) -> u8 { self.data[0] }
+
+    pub fn request(&self) -> u8 { self.data[1] }
+
+    pub fn value(&self) -> u16 { self.data[2] as u16 | (self.data[3] as u16) << 8 }
+
+    pub fn index(&self) -> u16 { self.data[4] as u16 | (self.data[5] as u16) << 8 }
+
+    pub fn length(&self) -> u16 { self.data[6] as u16 | (self.data[7] as u16) << 8 }
+
+    /// The payload following the 8-byte setup packet.
+    pub fn data(&self) -> &[u8] { &self.data[8..] }
+
+    /// The payload following the 8-byte setup packet.
+    pub fn data_mut(&mut self) -> &mut [u8] { &mut self.data[8..] }
+}
+
+impl TransferBuffer for ControlSetup {
+    fn as_ptr(&mut self) -> *mut u8 { self.data.as_mut_ptr() }
+    fn len(&self) -> usize { self.data.len() }
+    fn data_offset(&self) -> usize { 8 }
+}
 
 /// An asynchronous transfer that is not currently pending.
 /// Specifies the data necessary to perform a transfer on a specified endpoint, and holds the
 /// result of a completed transfer. A completed Transfer can be resubmitted.
-pub struct Transfer<'d> {
+pub struct Transfer<'d, B: TransferBuffer + 'd = Vec<u8>> {
     _handle: PhantomData<&'d ::DeviceHandle<'d>>,  // transfer.dev_handle
-    buffer: Vec<u8>, // move buffer into transfer
+    buffer: B, // move buffer into transfer
     transfer: *mut ::libusb::libusb_transfer,
 }
 
@@ -44,54 +148,104 @@ pub enum TransferStatus {
     Unknown = -1 as isize,
 }
 
-impl<'d> Transfer<'d> {
-    fn new(handle: &'d ::DeviceHandle<'d>, endpoint: u8, transfer_type: c_uchar, mut buffer: Vec<u8>, timeout: Duration) -> Transfer<'d> {
+/// Flags controlling how a `Transfer` is processed by libusb, corresponding to
+/// `libusb_transfer_flags`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TransferFlags(u8);
+
+impl TransferFlags {
+    /// Report a short read as `TransferStatus::Error` rather than `TransferStatus::Success`.
+    pub const SHORT_NOT_OK: TransferFlags = TransferFlags(::libusb::LIBUSB_TRANSFER_SHORT_NOT_OK);
+
+    /// Append a zero-length packet to terminate an OUT transfer whose length is a multiple
+    /// of the endpoint's max packet size.
+    pub const ADD_ZERO_PACKET: TransferFlags = TransferFlags(::libusb::LIBUSB_TRANSFER_ADD_ZERO_PACKET);
+
+    /// No flags set.
+    pub fn empty() -> TransferFlags { TransferFlags(0) }
+
+    /// The raw `libusb_transfer_flags` bits.
+    pub fn bits(&self) -> u8 { self.0 }
+
+    /// Builds `TransferFlags` from raw bits, discarding any that don't correspond to a flag
+    /// defined here.
+    pub fn from_bits_truncate(bits: u8) -> TransferFlags {
+        TransferFlags(bits & (Self::SHORT_NOT_OK.0 | Self::ADD_ZERO_PACKET.0))
+    }
+
+    /// Returns whether every flag set in `other` is also set in `self`.
+    pub fn contains(&self, other: TransferFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl ::std::ops::BitOr for TransferFlags {
+    type Output = TransferFlags;
+    fn bitor(self, rhs: TransferFlags) -> TransferFlags { TransferFlags(self.0 | rhs.0) }
+}
+
+fn status_from_libusb(status: c_int) -> TransferStatus {
+    match status {
+        ::libusb::LIBUSB_TRANSFER_COMPLETED => TransferStatus::Success,
+        ::libusb::LIBUSB_TRANSFER_ERROR => TransferStatus::Error,
+        ::libusb::LIBUSB_TRANSFER_TIMED_OUT => TransferStatus::Timeout,
+        ::libusb::LIBUSB_TRANSFER_CANCELLED => TransferStatus::Cancelled,
+        ::libusb::LIBUSB_TRANSFER_STALL => TransferStatus::Stall,
+        ::libusb::LIBUSB_TRANSFER_NO_DEVICE => TransferStatus::NoDevice,
+        ::libusb::LIBUSB_TRANSFER_OVERFLOW => TransferStatus::Overflow,
+        _ => TransferStatus::Unknown,
+    }
+}
+
+impl<'d, B: TransferBuffer + 'd> Transfer<'d, B> {
+    fn new(handle: &'d ::DeviceHandle<'d>, endpoint: u8, transfer_type: c_uchar, buffer: B, timeout: Duration) -> Transfer<'d, B> {
+        Transfer::new_with_iso_packets(handle, endpoint, transfer_type, buffer, 0, timeout)
+    }
+
+    fn new_with_iso_packets(handle: &'d ::DeviceHandle<'d>, endpoint: u8, transfer_type: c_uchar, mut buffer: B, num_iso_packets: c_int, timeout: Duration) -> Transfer<'d, B> {
         let timeout_ms = timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000;
         unsafe {
-            let t = ::libusb::libusb_alloc_transfer(0);
+            let t = ::libusb::libusb_alloc_transfer(num_iso_packets);
             (*t).status = -1;
             (*t).dev_handle = handle.as_raw();
             (*t).endpoint = endpoint as c_uchar;
             (*t).transfer_type = transfer_type;
             (*t).timeout = timeout_ms as c_uint;
-            (*t).buffer = buffer.as_mut_ptr();
-            (*t).length = buffer.len() as i32;
+            (*t).length = TransferBuffer::len(&buffer) as i32;
             (*t).actual_length = 0;
+            (*t).num_iso_packets = num_iso_packets;
+            (*t).buffer = buffer.as_ptr();
 
-            buffer.shrink_to_fit();
             Transfer{ transfer: t, _handle: PhantomData, buffer: buffer }
         }
     }
 
     /// Creates an asynchronous bulk transfer, but does not submit it.
-    pub fn bulk(handle: &'d ::DeviceHandle<'d>, endpoint: u8, buffer: Vec<u8>, timeout: Duration) -> Transfer<'d> {
+    pub fn bulk(handle: &'d ::DeviceHandle<'d>, endpoint: u8, buffer: B, timeout: Duration) -> Transfer<'d, B> {
         Transfer::new(handle, endpoint, ::libusb::LIBUSB_TRANSFER_TYPE_BULK, buffer, timeout)
     }
 
     /// Creates an asynchronous interrupt transfer, but does not submit it.
-    pub fn interrupt(handle: &'d ::DeviceHandle<'d>, endpoint: u8, buffer: Vec<u8>, timeout: Duration) -> Transfer<'d> {
+    pub fn interrupt(handle: &'d ::DeviceHandle<'d>, endpoint: u8, buffer: B, timeout: Duration) -> Transfer<'d, B> {
         Transfer::new(handle, endpoint, ::libusb::LIBUSB_TRANSFER_TYPE_INTERRUPT, buffer, timeout)
     }
 
-    /// Creates an asynchronous control transfer, but does not submit it.
-    /// In difference to the other functions, this function takes  additional arguments.
-    /// The additional arguments are the ones also used in the synchron version
-    /// of read_control / write_control.
-    pub fn control(handle: &'d ::DeviceHandle<'d>, endpoint: u8, buffer: Vec<u8>,
-                   request_type: u8, request: u8, value: u16, index: u16,
-                   timeout: Duration) -> Transfer<'d> {
-        let length = buffer.len() as u16;
-        let vec: Vec<u8> = [
-            request_type,
-            request,
-            (value & 0xff) as u8,
-            (value >> 8) as u8,
-            (index & 0xff) as u8,
-            (index >> 8) as u8,
-            (length & 0xff) as u8,
-            (length >> 8) as u8,
-        ].iter().cloned().chain(buffer).collect();
-        Transfer::new(handle, endpoint, ::libusb::LIBUSB_TRANSFER_TYPE_CONTROL, vec, timeout)
+    /// Creates an asynchronous isochronous transfer, but does not submit it.
+    ///
+    /// `num_iso_packets` is the number of isochronous packets to allocate, and `packet_len` is
+    /// the size in bytes of each one; `buffer` must be at least `num_iso_packets * packet_len`
+    /// bytes long. Unlike the other transfer types, status and actual length are reported per
+    /// packet, not once for the whole transfer -- use `iso_packets` and `iso_packet` to read
+    /// the per-packet results after the transfer completes.
+    pub fn isochronous(handle: &'d ::DeviceHandle<'d>, endpoint: u8, buffer: B,
+                        num_iso_packets: usize, packet_len: usize, timeout: Duration) -> Transfer<'d, B> {
+        assert!(TransferBuffer::len(&buffer) >= num_iso_packets * packet_len);
+        let t = Transfer::new_with_iso_packets(handle, endpoint, ::libusb::LIBUSB_TRANSFER_TYPE_ISOCHRONOUS,
+                                                buffer, num_iso_packets as c_int, timeout);
+        unsafe {
+            ::libusb::libusb_set_iso_packet_lengths(t.transfer, packet_len as c_uint);
+        }
+        t
     }
 
     pub fn endpoint(&self) -> u8 {
@@ -106,120 +260,254 @@ impl<'d> Transfer<'d> {
         }
     }
 
+    /// Sets the libusb transfer flags.
+    ///
+    /// `LIBUSB_TRANSFER_FREE_BUFFER` and `LIBUSB_TRANSFER_FREE_TRANSFER` are always masked off,
+    /// since this crate owns the buffer's and transfer's lifetime itself.
+    pub fn set_flags(&mut self, flags: TransferFlags) {
+        let bits = flags.bits() & !(::libusb::LIBUSB_TRANSFER_FREE_BUFFER | ::libusb::LIBUSB_TRANSFER_FREE_TRANSFER);
+        unsafe { (*self.transfer).flags = bits; }
+    }
+
+    /// Gets the currently set libusb transfer flags.
+    pub fn flags(&self) -> TransferFlags {
+        TransferFlags::from_bits_truncate(unsafe { (*self.transfer).flags })
+    }
+
     /// Gets the status of a completed transfer.
     pub fn status(&self) -> TransferStatus {
-        match unsafe { (*self.transfer).status } {
-            ::libusb::LIBUSB_TRANSFER_COMPLETED => TransferStatus::Success,
-            ::libusb::LIBUSB_TRANSFER_ERROR => TransferStatus::Error,
-            ::libusb::LIBUSB_TRANSFER_TIMED_OUT => TransferStatus::Timeout,
-            ::libusb::LIBUSB_TRANSFER_CANCELLED => TransferStatus::Cancelled,
-            ::libusb::LIBUSB_TRANSFER_STALL => TransferStatus::Stall,
-            ::libusb::LIBUSB_TRANSFER_NO_DEVICE => TransferStatus::NoDevice,
-            _ => TransferStatus::Unknown,
-        }
+        status_from_libusb(unsafe { (*self.transfer).status })
     }
 
     /// Access the buffer of a transfer.
     pub fn buffer(&mut self) -> &mut [u8] {
-        &mut self.buffer
+        self.buffer.as_mut_slice()
     }
 
     /// Replace the buffer of a transfer.
-    pub fn set_buffer(&mut self, mut buffer: Vec<u8>) {
+    pub fn set_buffer(&mut self, mut buffer: B) {
         unsafe {
-            (*self.transfer).buffer = buffer.as_mut_ptr();
-            (*self.transfer).length = buffer.len() as i32;
+            (*self.transfer).buffer = buffer.as_ptr();
+            (*self.transfer).length = TransferBuffer::len(&buffer) as i32;
             (*self.transfer).actual_length = 0;
         }
         self.buffer = buffer;
     }
 
     /// Access the slice of the buffer containing actual data received on an IN transfer.
-    pub fn actual(&mut self) -> &'d mut [u8] {
+    ///
+    /// For isochronous transfers, which report status and actual length per packet rather than
+    /// for the transfer as a whole, use `iso_packets` and `iso_packet` instead.
+    pub fn actual(&mut self) -> &mut [u8] {
         unsafe {
-            // if this is a control request, the first 8 bytes of the buffer are
-            // the setup header
-            let offset = match (*self.transfer).transfer_type {
-                ::libusb::LIBUSB_TRANSFER_TYPE_CONTROL => 8,
-                _ => 0
-            };
-            slice::from_raw_parts_mut((*self.transfer).buffer.offset(offset), (*self.transfer).actual_length as usize)
+            let offset = self.buffer.data_offset();
+            slice::from_raw_parts_mut(self.buffer.as_ptr().offset(offset as isize), (*self.transfer).actual_length as usize)
+        }
+    }
+
+    /// Returns the number of isochronous packets this transfer was allocated with.
+    ///
+    /// Zero for non-isochronous transfers.
+    pub fn iso_packets(&self) -> usize {
+        unsafe { (*self.transfer).num_iso_packets as usize }
+    }
+
+    /// Accesses the status and the received data of a single isochronous packet.
+    ///
+    /// Panics if `i >= self.iso_packets()`.
+    pub fn iso_packet(&mut self, i: usize) -> (TransferStatus, &mut [u8]) {
+        assert!(i < self.iso_packets());
+        unsafe {
+            let desc = (*self.transfer).iso_packet_desc.as_mut_ptr().offset(i as isize);
+            let status = status_from_libusb((*desc).status);
+            let buf = ::libusb::libusb_get_iso_packet_buffer_simple(self.transfer, i as c_uint);
+            let data = slice::from_raw_parts_mut(buf, (*desc).actual_length as usize);
+            (status, data)
         }
     }
 }
 
-impl<'d> Drop for Transfer<'d> {
+impl<'d> Transfer<'d, ControlSetup> {
+    /// Creates an asynchronous control transfer, but does not submit it.
+    pub fn control(handle: &'d ::DeviceHandle<'d>, endpoint: u8, setup: ControlSetup, timeout: Duration) -> Transfer<'d, ControlSetup> {
+        Transfer::new(handle, endpoint, ::libusb::LIBUSB_TRANSFER_TYPE_CONTROL, setup, timeout)
+    }
+}
+
+impl<'d, B: TransferBuffer + 'd> Drop for Transfer<'d, B> {
     fn drop(&mut self) {
         unsafe { ::libusb::libusb_free_transfer(self.transfer); }
     }
 }
 
-/// Internal type holding data touched by libusb completion callback.
-struct CallbackData {
+/// Internal type holding data touched by libusb completion callback, shared by every transfer
+/// submitted to a particular `AsyncGroup`.
+struct GroupData<'d, B: TransferBuffer + 'd> {
     /// Transfers that have completed, but haven't yet been returned from `wait_any`.
-    completed: Mutex<VecDeque<*mut ::libusb::libusb_transfer>>,
+    completed: Mutex<VecDeque<Box<Transfer<'d, B>>>>,
 
     /// Signals a completion to avoid race conditions between callback and
     /// `libusb_handle_events_completed`. This is synchronized with the
     /// Mutex above, but can't be included in it because libusb reads it
     /// without the lock held.
     flag: UnsafeCell<c_int>,
+
+    /// The set of pending transfers, submitted through either `submit` or
+    /// `submit_with_callback`. Kept here, rather than on `AsyncGroup` directly, so the
+    /// completion callback can remove a transfer as soon as it fires, regardless of which
+    /// submission path it came from.
+    pending: Mutex<HashSet<*mut ::libusb::libusb_transfer>>,
 }
 
-/// An AsyncGroup manages outstanding asynchronous transfers.
-pub struct AsyncGroup<'d> {
-    context: &'d ::Context,
+/// Per-transfer data pointed to by `libusb_transfer::user_data`. Boxed so the callback can
+/// reconstruct and consume it with `Box::from_raw`.
+struct UserData<'d, B: TransferBuffer + 'd> {
+    group: *const GroupData<'d, B>,
+    alive: Arc<Mutex<bool>>,
+    transfer: Box<Transfer<'d, B>>,
+    callback: Option<Box<FnMut(Transfer<'d, B>) + 'd>>,
+}
 
-    /// The data touched by the callback, boxed to keep a consistent address if the AsyncGroup
-    /// is moved while transfers are active.
-    callback_data: Box<CallbackData>,
+/// A handle to a single transfer submitted to an `AsyncGroup`, allowing it to be cancelled
+/// independently of the rest of the group.
+#[derive(Clone)]
+pub struct TransferCanceller {
+    transfer: *mut ::libusb::libusb_transfer,
+    alive: Arc<Mutex<bool>>,
+}
 
-    /// The set of pending transfers. We need to keep track of them so they can be cancelled on
-    /// drop.
-    pending: HashSet<*mut ::libusb::libusb_transfer>,
+// `libusb_cancel_transfer` may safely be called from any thread.
+unsafe impl Send for TransferCanceller {}
+unsafe impl Sync for TransferCanceller {}
+
+impl TransferCanceller {
+    /// Cancels the transfer. Does nothing if the transfer has already completed.
+    pub fn cancel(&self) {
+        // Locking `alive` makes this atomic with respect to `async_group_callback` marking the
+        // transfer dead and (for `submit_with_callback`) handing it off to be freed, so this
+        // never calls into libusb on a transfer that's already been freed.
+        let alive = self.alive.lock().unwrap();
+        if *alive {
+            unsafe { ::libusb::libusb_cancel_transfer(self.transfer); }
+        }
+    }
 }
 
 /// The libusb transfer completion callback. Careful: libusb may call this on any thread!
-extern "C" fn async_group_callback(transfer: *mut ::libusb::libusb_transfer) {
+extern "C" fn async_group_callback<'d, B: TransferBuffer + 'd>(transfer: *mut ::libusb::libusb_transfer) {
     unsafe {
-        let callback_data: &CallbackData = &*((*transfer).user_data as *const CallbackData);
-        let mut completed = callback_data.completed.lock().unwrap();
-        completed.push_back(transfer);
-        *(callback_data.flag.get()) = 1;
+        let user_data = Box::from_raw((*transfer).user_data as *mut UserData<'d, B>);
+        let mut alive = user_data.alive.lock().unwrap();
+        *alive = false;
+
+        let group: &GroupData<'d, B> = &*user_data.group;
+
+        match user_data.callback {
+            Some(mut callback) => {
+                group.pending.lock().unwrap().remove(&transfer);
+                callback(*user_data.transfer);
+            }
+            None => {
+                {
+                    let mut completed = group.completed.lock().unwrap();
+                    completed.push_back(user_data.transfer);
+                    *(group.flag.get()) = 1;
+                }
+                // Only drop this transfer from `pending` once it's sitting in `completed`, so
+                // `pending` and `completed` never both read empty while it is still uncollected.
+                group.pending.lock().unwrap().remove(&transfer);
+            }
+        }
     }
 }
 
-impl<'d> AsyncGroup<'d> {
+/// An AsyncGroup manages outstanding asynchronous transfers.
+pub struct AsyncGroup<'d, B: TransferBuffer + 'd = Vec<u8>> {
+    context: &'d ::Context,
+
+    /// The data touched by the callback, boxed to keep a consistent address if the AsyncGroup
+    /// is moved while transfers are active.
+    data: Box<GroupData<'d, B>>,
+}
+
+impl<'d, B: TransferBuffer + 'd> AsyncGroup<'d, B> {
     /// Creates an AsyncGroup to process transfers for devices from the given context.
-    pub fn new(context: &'d ::Context) -> AsyncGroup<'d> {
+    pub fn new(context: &'d ::Context) -> AsyncGroup<'d, B> {
         AsyncGroup {
             context: context,
-            callback_data: Box::new(CallbackData {
+            data: Box::new(GroupData {
                 completed: Mutex::new(VecDeque::new()),
                 flag: UnsafeCell::new(0),
+                pending: Mutex::new(HashSet::new()),
             }),
-            pending: HashSet::new(),
         }
     }
 
+    /// Whether there are no transfers outstanding or waiting to be collected. Used to avoid
+    /// blocking forever in `wait_any`/`wait_any_timeout`/`try_wait_any` when there's nothing left
+    /// for them to ever return -- `pending` alone isn't enough, since a transfer is removed from
+    /// it as soon as it lands in `completed`, before a caller has had a chance to collect it.
+    fn has_no_transfers(&self) -> bool {
+        self.data.pending.lock().unwrap().is_empty() && self.data.completed.lock().unwrap().is_empty()
+    }
+
+    fn submit_internal(&mut self, t: Transfer<'d, B>, callback: Option<Box<FnMut(Transfer<'d, B>) + 'd>>) -> ::Result<TransferCanceller> {
+        let alive = Arc::new(Mutex::new(true));
+        let transfer = t.transfer;
+        let user_data = Box::new(UserData {
+            group: &*self.data as *const GroupData<'d, B>,
+            alive: alive.clone(),
+            transfer: Box::new(t),
+            callback: callback,
+        });
+        let user_data = Box::into_raw(user_data);
+
+        unsafe {
+            (*transfer).user_data = user_data as *mut c_void;
+            (*transfer).callback = async_group_callback::<'d, B>;
+            match ::libusb::libusb_submit_transfer(transfer) {
+                0 => (),
+                err => {
+                    // Reclaim the box (and the Transfer inside it) so they are dropped instead
+                    // of leaked.
+                    drop(Box::from_raw(user_data));
+                    return Err(::error::from_libusb(err));
+                }
+            }
+        }
+
+        let canceller = TransferCanceller { transfer: transfer, alive: alive };
+        self.data.pending.lock().unwrap().insert(transfer);
+        Ok(canceller)
+    }
+
     /// Starts a transfer.
     ///
     /// The Transfer is owned by the AsyncGroup while it is pending, and is
     /// returned from `wait_any` when it completes or fails.
-    pub fn submit(&mut self, t: Transfer<'d>) -> ::Result<()> {
-        unsafe {
-            (*t.transfer).user_data = &mut *self.callback_data as *mut _ as *mut c_void;
-            (*t.transfer).callback = async_group_callback;
-            try_unsafe!(::libusb::libusb_submit_transfer(t.transfer));
-            self.pending.insert(t.transfer);
-            mem::forget(t);
-            Ok(())
-        }
+    pub fn submit(&mut self, t: Transfer<'d, B>) -> ::Result<TransferCanceller> {
+        self.submit_internal(t, None)
     }
 
-    /// Waits for any pending transfer to complete, and return it.
-    pub fn wait_any(&mut self) -> ::Result<Transfer<'d>> {
-        if self.pending.len() == 0 {
+    /// Starts a transfer, invoking `callback` with it when it completes instead of returning it
+    /// from `wait_any`.
+    ///
+    /// This lets a caller react to or resubmit a transfer inline, without a central `wait_any`
+    /// loop. Note that `callback` runs on whatever thread is processing libusb events (often the
+    /// thread calling `wait_any` on some other transfer in the group, or the caller's own event
+    /// loop thread), never a thread of its own.
+    pub fn submit_with_callback<F>(&mut self, t: Transfer<'d, B>, callback: F) -> ::Result<TransferCanceller>
+        where F: FnMut(Transfer<'d, B>) + 'd
+    {
+        self.submit_internal(t, Some(Box::new(callback)))
+    }
+
+    /// Waits for any pending transfer submitted through `submit` to complete, and return it.
+    ///
+    /// Transfers submitted through `submit_with_callback` are not returned here; they are
+    /// delivered to their callback instead.
+    pub fn wait_any(&mut self) -> ::Result<Transfer<'d, B>> {
+        if self.has_no_transfers() {
             // Otherwise this function would block forever waiting for a transfer to complete
             return Err(::Error::NotFound)
         }
@@ -228,26 +516,76 @@ impl<'d> AsyncGroup<'d> {
             let transfer;
             loop {
                 {
-                    let mut completed = self.callback_data.completed.lock().unwrap();
+                    let mut completed = self.data.completed.lock().unwrap();
                     if let Some(t) = completed.pop_front() {
                         transfer = t;
                         break;
                     }
-                    *self.callback_data.flag.get() = 0;
+                    // Any transfers still pending may all be `submit_with_callback` ones; once
+                    // they have finished there is nothing left for this function to return.
+                    if self.data.pending.lock().unwrap().is_empty() {
+                        return Err(::Error::NotFound)
+                    }
+                    *self.data.flag.get() = 0;
                 }
                 try_unsafe!(::libusb::libusb_handle_events_completed(
                     self.context.as_raw(),
-                    self.callback_data.flag.get()
+                    self.data.flag.get()
                 ));
             }
 
-            if !self.pending.remove(&transfer) {
-                panic!("Got a completion for a transfer that wasn't pending");
+            Ok(*transfer)
+        }
+    }
+
+    /// Waits up to `timeout` for any pending transfer submitted through `submit` to complete.
+    ///
+    /// Returns `Ok(None)` if the timeout elapses before anything completes. This lets a caller
+    /// with its own event loop, or a need for responsive shutdown, poll an `AsyncGroup` instead
+    /// of blocking in `wait_any` indefinitely.
+    pub fn wait_any_timeout(&mut self, timeout: Duration) -> ::Result<Option<Transfer<'d, B>>> {
+        if self.has_no_transfers() {
+            // Otherwise this function would block forever waiting for a transfer to complete
+            return Err(::Error::NotFound)
+        }
+
+        unsafe {
+            {
+                let mut completed = self.data.completed.lock().unwrap();
+                if let Some(t) = completed.pop_front() {
+                    return Ok(Some(*t));
+                }
+                *self.data.flag.get() = 0;
             }
-            
-            let vec = Vec::from_raw_parts((*transfer).buffer, (*transfer).length as usize, (*transfer).length as usize);
-            Ok(Transfer{ transfer: transfer, _handle: PhantomData, buffer: vec })
+
+            let mut tv = timeval {
+                tv_sec: timeout.as_secs() as time_t,
+                tv_usec: (timeout.subsec_nanos() / 1_000) as suseconds_t,
+            };
+
+            try_unsafe!(::libusb::libusb_handle_events_timeout_completed(
+                self.context.as_raw(),
+                &mut tv,
+                self.data.flag.get()
+            ));
+        }
+
+        Ok(self.data.completed.lock().unwrap().pop_front().map(|t| *t))
+    }
+
+    /// Returns a transfer that has already completed, without entering libusb or blocking.
+    ///
+    /// Returns `Ok(None)` if nothing has completed yet. This only drains the queue filled by the
+    /// completion callback; pair it with an external event loop that drives libusb's file
+    /// descriptors, or with periodic calls to `wait_any_timeout` on another `AsyncGroup`, to
+    /// actually make progress.
+    pub fn try_wait_any(&mut self) -> ::Result<Option<Transfer<'d, B>>> {
+        if self.has_no_transfers() {
+            return Err(::Error::NotFound)
         }
+
+        let mut completed = self.data.completed.lock().unwrap();
+        Ok(completed.pop_front().map(|t| *t))
     }
 
     /// Cancels all pending transfers.
@@ -255,20 +593,125 @@ impl<'d> AsyncGroup<'d> {
     /// Throws away any received data and errors on transfers that have completed, but haven't been
     /// collected by `wait_any`.
     pub fn cancel_all(&mut self) -> ::Result<()> {
-        for &transfer in self.pending.iter() {
+        let pending: Vec<_> = self.data.pending.lock().unwrap().iter().cloned().collect();
+        for transfer in pending {
             try_unsafe!(::libusb::libusb_cancel_transfer(transfer))
         }
 
-        while self.pending.len() > 0 {
-            try!(self.wait_any());
+        unsafe {
+            while self.data.pending.lock().unwrap().len() > 0 {
+                *self.data.flag.get() = 0;
+                try_unsafe!(::libusb::libusb_handle_events_completed(
+                    self.context.as_raw(),
+                    self.data.flag.get()
+                ));
+            }
         }
 
+        // Drop anything left in the completed queue that `wait_any` never collected.
+        self.data.completed.lock().unwrap().clear();
+
         Ok(())
     }
 }
 
-impl<'d> Drop for AsyncGroup<'d> {
+impl<'d, B: TransferBuffer + 'd> Drop for AsyncGroup<'d, B> {
     fn drop(&mut self) {
         self.cancel_all().ok();
     }
 }
+
+fn error_from_status(status: TransferStatus) -> ::Error {
+    match status {
+        TransferStatus::Success => unreachable!("Success is not an error"),
+        TransferStatus::Error => ::Error::Io,
+        TransferStatus::Timeout => ::Error::Timeout,
+        TransferStatus::Cancelled => ::Error::Io,
+        TransferStatus::Stall => ::Error::Pipe,
+        TransferStatus::NoDevice => ::Error::NoDevice,
+        TransferStatus::Overflow => ::Error::Overflow,
+        TransferStatus::Unknown => ::Error::Other,
+    }
+}
+
+/// A continuous stream of bulk transfers on a single endpoint, built on top of `AsyncGroup`.
+///
+/// `BulkStream` keeps a ring of `num_transfers` buffers of `transfer_size` bytes submitted:
+/// each call to `next` collects one completed transfer, resubmits it with a fresh zero-filled
+/// buffer, and then hands its data to the caller, while the rest of the ring stays in flight.
+/// Use this instead of manually resubmitting transfers returned from `AsyncGroup::wait_any`
+/// for continuous capture workloads. Every submitted buffer is zero-filled; there is no way to
+/// supply data for an OUT endpoint.
+pub struct BulkStream<'d> {
+    group: AsyncGroup<'d, Vec<u8>>,
+    transfer_size: usize,
+    transferred_bytes: u64,
+    submitted_bytes: u64,
+    short_packets: u64,
+    stopped: bool,
+}
+
+impl<'d> BulkStream<'d> {
+    /// Allocates `num_transfers` buffers of `transfer_size` bytes and submits them all on
+    /// `endpoint`.
+    pub fn new(context: &'d ::Context, handle: &'d ::DeviceHandle<'d>, endpoint: u8,
+               num_transfers: usize, transfer_size: usize, timeout: Duration) -> ::Result<BulkStream<'d>> {
+        let mut group = AsyncGroup::new(context);
+
+        for _ in 0..num_transfers {
+            let buffer = vec![0; transfer_size];
+            let t = Transfer::bulk(handle, endpoint, buffer, timeout);
+            try!(group.submit(t));
+        }
+
+        Ok(BulkStream {
+            group: group,
+            transfer_size: transfer_size,
+            transferred_bytes: 0,
+            submitted_bytes: (num_transfers * transfer_size) as u64,
+            short_packets: 0,
+            stopped: false,
+        })
+    }
+
+    /// Waits for the next transfer to complete, returning its data and resubmitting it.
+    ///
+    /// Returns `Ok(None)` once the stream has stopped after an error. Once a transfer fails,
+    /// the stream stops resubmitting further transfers and returns the error once; subsequent
+    /// calls return `Ok(None)`. The transfers still in flight at that point are cancelled when
+    /// the `BulkStream` is dropped.
+    pub fn next(&mut self) -> ::Result<Option<Vec<u8>>> {
+        if self.stopped {
+            return Ok(None);
+        }
+
+        let mut t = try!(self.group.wait_any());
+        let status = t.status();
+        if status != TransferStatus::Success {
+            self.stopped = true;
+            return Err(error_from_status(status));
+        }
+
+        let actual = t.actual();
+        self.transferred_bytes += actual.len() as u64;
+        if actual.len() < self.transfer_size {
+            self.short_packets += 1;
+        }
+        let data = actual.to_vec();
+
+        t.set_buffer(vec![0; self.transfer_size]);
+        try!(self.group.submit(t));
+        self.submitted_bytes += self.transfer_size as u64;
+
+        Ok(Some(data))
+    }
+
+    /// Total bytes received across all completed transfers.
+    pub fn transferred_bytes(&self) -> u64 { self.transferred_bytes }
+
+    /// Total bytes submitted across all transfers, completed or still in flight.
+    pub fn submitted_bytes(&self) -> u64 { self.submitted_bytes }
+
+    /// Number of completions whose actual length was less than the transfer's buffer size.
+    pub fn short_packets(&self) -> u64 { self.short_packets }
+}